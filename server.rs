@@ -2,20 +2,108 @@
 // The tokio library provides asynchronous runtime for managing tasks and I/O operations efficiently
 use tokio::{
     net::{TcpListener, TcpStream}, // Asynchronous TCP networking
-    sync::broadcast,              // Broadcast channel for communication between tasks
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, // Async I/O utilities
+    sync::{broadcast, mpsc},      // Broadcast channel per room, mpsc channel for direct messages
+    task::JoinSet,                // Tracks spawned connection tasks so shutdown can await them
 };
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec}; // Length-prefixed framing
+use tokio_serde::formats::SymmetricalJson; // JSON (de)serialization layer over the frames
+use tokio_serde::SymmetricallyFramed; // Glue that turns a byte transport into a typed one
+use futures::{SinkExt, StreamExt}; // `send`/`next` for the sink and stream halves
 use serde::{Serialize, Deserialize}; // Serialization and deserialization for structured data
 use chrono::Local; // For working with local date and time
+use uuid::Uuid; // Stable per-session identifier, independent of the chosen display name
+use std::collections::HashMap; // Maps usernames to DM senders and room names to channels
 use std::error::Error; // Error handling trait
+use std::sync::{Arc, Mutex}; // Shared ownership and interior mutability for the shared state
+
+// The room every client starts in before issuing any `/join` command.
+const DEFAULT_ROOM: &str = "lobby";
+
+// Shared state visible to every connection task.
+// Borrowing the design from Tokio's own chat example, we keep a registry of the
+// currently online users so that we can deliver messages to a specific person
+// instead of always broadcasting to everyone, plus a registry of rooms so that
+// clients can be partitioned into independent broadcast channels.
+struct Shared {
+    // Maps each online session id to its display name and the sender half of
+    // its private channel. Keyed by `Uuid` rather than username so that two
+    // sessions can never steal or delete each other's route by picking the
+    // same display name; `/who` and `/w` resolve usernames by scanning the
+    // (small, in-memory) map.
+    peers: Mutex<HashMap<Uuid, Peer>>,
+    // Maps each room name to its broadcast channel, created lazily on first join.
+    rooms: Mutex<HashMap<String, broadcast::Sender<Broadcast>>>,
+}
+
+// A single online session: its chosen display name plus the channel used to
+// deliver direct messages to it.
+struct Peer {
+    username: String,
+    tx: mpsc::UnboundedSender<ChatMessage>,
+}
+
+impl Shared {
+    // Create an empty registry.
+    fn new() -> Self {
+        Shared {
+            peers: Mutex::new(HashMap::new()),
+            rooms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Return the broadcast sender for `room`, creating the channel the first time
+    // a room is referenced so rooms spring into existence on demand.
+    fn room(&self, room: &str) -> broadcast::Sender<Broadcast> {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms
+            .entry(room.to_string())
+            .or_insert_with(|| broadcast::channel::<Broadcast>(100).0)
+            .clone()
+    }
+
+    // Drop `room`'s entry once nobody is subscribed to it anymore, so a client
+    // joining an unbounded number of distinct room names doesn't leak a
+    // HashMap entry and broadcast channel for each one.
+    fn prune_room(&self, room: &str) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(tx) = rooms.get(room) {
+            if tx.receiver_count() == 0 {
+                rooms.remove(room);
+            }
+        }
+    }
+}
+
+// Explicit model of the server's wire protocol, in the spirit of blastmud's
+// listener: rather than passing bare strings around, each meaningful thing a
+// session does is named. Events are keyed by the session's `Uuid` so the rest
+// of the server can attribute activity to a stable identity even when two users
+// pick the same display name.
+#[derive(Debug, Clone)]
+enum ServerEvent {
+    Connected { id: Uuid, username: String }, // A socket identified itself.
+    SentLine { id: Uuid, content: String },   // A session submitted a line of input.
+    Disconnected { id: Uuid },                // A session closed or dropped.
+}
+
+// Payload carried over a room's broadcast channel. Normal traffic is a `Chat`
+// message; `Shutdown` is a control signal fanned out to every session when the
+// server is asked to stop.
+#[derive(Debug, Clone)]
+enum Broadcast {
+    Chat(ChatMessage), // An ordinary chat/system message destined for the room.
+    Shutdown,          // The server is shutting down; flush a goodbye and close.
+}
 
 // Define the structure of chat messages
 // This matches the data structure expected by clients and simplifies message handling
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct ChatMessage {
+    id: Uuid,                 // Stable session id of the sender (nil for server-originated messages)
     username: String,         // Name of the user sending the message
     content: String,          // Content of the message
     timestamp: String,        // Timestamp of when the message was sent
+    room: String,             // Room the message belongs to
     message_type: MessageType, // Type of message (user or system notification)
 }
 
@@ -31,7 +119,7 @@ enum MessageType {
 async fn main() -> Result<(), Box<dyn Error>> {
     // Bind the server to the specified IP and port
     let listener = TcpListener::bind("127.0.0.1:8082").await?;
-    
+
     // Display server startup message with formatting
     println!("╔════════════════════════════════════════╗");
     println!("║        RETRO CHAT SERVER ACTIVE        ║");
@@ -39,95 +127,410 @@ async fn main() -> Result<(), Box<dyn Error>> {
     println!("║        Press Ctrl+C to shutdown        ║");
     println!("╚════════════════════════════════════════╝");
 
-    // Create a broadcast channel for message distribution
-    // This allows multiple subscribers to receive the same messages
-    let (tx, _) = broadcast::channel::<String>(100); // Buffer size of 100 messages
+    // Create the shared state (peer registry + room channels) and wrap it in an
+    // Arc so every connection task can share it.
+    let state = Arc::new(Shared::new());
+
+    // Tracks every spawned connection task so shutdown can wait on the ones
+    // still running instead of guessing how long they need to drain.
+    let mut connections = JoinSet::new();
 
-    // Main server loop to handle incoming connections
+    // Main server loop: accept connections until interrupted with Ctrl+C.
     loop {
-        let (socket, addr) = listener.accept().await?; // Accept a new connection
-        
-        // Display connection information
-        println!("💀[{}] New connection", Local::now().format("%H:%M:%S"));
-        println!("💀 Address: {}", addr);
-
-        // Clone sender for this connection and subscribe a receiver
-        let tx = tx.clone();
-        let rx = tx.subscribe();
-
-        // Spawn a new task to handle this connection asynchronously
-        tokio::spawn(async move {
-            handle_connection(socket, tx, rx).await
-        });
+        tokio::select! {
+            // Accept a new connection.
+            result = listener.accept() => {
+                let (socket, addr) = result?;
+
+                // Assign each accepted socket a stable session id before spawning its task.
+                let id = Uuid::new_v4();
+
+                // Display connection information
+                println!("💀[{}] New connection ({})", Local::now().format("%H:%M:%S"), id);
+                println!("💀 Address: {}", addr);
+
+                let state = Arc::clone(&state); // Share the shared state with the task
+
+                // Spawn a new task to handle this connection asynchronously
+                connections.spawn(async move {
+                    handle_connection(socket, id, state).await
+                });
+            }
+            // React to Ctrl+C: tell every room to shut down, stop accepting, and drain.
+            _ = tokio::signal::ctrl_c() => {
+                println!("💀[{}] Shutdown signal received", Local::now().format("%H:%M:%S"));
+                let rooms = state.rooms.lock().unwrap();
+                for tx in rooms.values() {
+                    let _ = tx.send(Broadcast::Shutdown);
+                }
+                break;
+            }
+        }
+    }
+
+    // Wait for every in-flight connection task to flush its goodbye frame and
+    // exit on its own, bounded so a stuck task can't hang the shutdown forever.
+    let drain = async {
+        while connections.join_next().await.is_some() {}
+    };
+    if tokio::time::timeout(std::time::Duration::from_secs(5), drain).await.is_err() {
+        println!("💀[{}] timed out waiting for connections to drain", Local::now().format("%H:%M:%S"));
     }
+    Ok(())
 }
 
 // Function to handle individual client connections
 async fn handle_connection(
-    mut socket: TcpStream,               // The TCP connection for the client
-    tx: broadcast::Sender<String>,      // Sender for broadcasting messages
-    mut rx: broadcast::Receiver<String>, // Receiver for incoming broadcasts
+    socket: TcpStream,  // The TCP connection for the client
+    id: Uuid,           // Stable session id for this connection
+    state: Arc<Shared>, // Shared peer registry and room channels
 ) {
-    // Split the socket into reader and writer parts
-    let (reader, mut writer) = socket.split();
-    let mut reader = BufReader::new(reader); // Buffer the reader for efficient I/O
-    let mut username = String::new(); // Store the username sent by the client
-
-    // Read the username sent by the client
-    reader.read_line(&mut username).await.unwrap();
-    let username = username.trim().to_string(); // Remove extra spaces or newlines
-
-    // Send a system notification indicating the user has joined
-    let join_msg = ChatMessage {
-        username: username.clone(),
-        content: "joined the chat".to_string(),
-        timestamp: Local::now().format("%H:%M:%S").to_string(),
-        message_type: MessageType::SystemNotification,
+    // Split the socket into owned halves and wrap each in a length-delimited,
+    // JSON-typed transport. Every frame on the wire is a length-prefixed
+    // `ChatMessage`, so newlines in message bodies are no longer special and
+    // clients never have to reassemble partial lines by hand.
+    let (rd, wr) = socket.into_split();
+    let mut reader = SymmetricallyFramed::new(
+        FramedRead::new(rd, LengthDelimitedCodec::new()),
+        SymmetricalJson::<ChatMessage>::default(),
+    );
+    let mut writer = SymmetricallyFramed::new(
+        FramedWrite::new(wr, LengthDelimitedCodec::new()),
+        SymmetricalJson::<ChatMessage>::default(),
+    );
+
+    // The first frame carries the client's chosen username.
+    let username = match reader.next().await {
+        Some(Ok(first)) => first.username.trim().to_string(),
+        _ => return, // Client hung up before identifying itself.
+    };
+    log_event(&ServerEvent::Connected { id, username: username.clone() });
+
+    // Reject the login outright if another online session already holds this
+    // display name, so two sessions can never clobber or steal each other's
+    // route by colliding on a username. The check and the insert happen under
+    // a single lock acquisition (with the guard fully dropped before the block
+    // ends) so two sessions racing to claim the same name can't both pass the
+    // check before either is registered.
+    let (dm_tx, mut dm_rx) = mpsc::unbounded_channel::<ChatMessage>();
+    let taken = {
+        let mut peers = state.peers.lock().unwrap();
+        if peers.values().any(|p| p.username == username) {
+            true
+        } else {
+            peers.insert(id, Peer { username: username.clone(), tx: dm_tx });
+            false
+        }
     };
-    let join_json = serde_json::to_string(&join_msg).unwrap();
-    tx.send(join_json).unwrap();
+    if taken {
+        let msg = system_message(Uuid::nil(), "server", &format!("username {:?} is already taken", username), DEFAULT_ROOM);
+        let _ = writer.send(msg).await;
+        return;
+    }
+
+    // Start out in the default room: subscribe to its channel and announce the join.
+    let mut room = DEFAULT_ROOM.to_string();
+    let mut tx = state.room(&room);
+    let mut rx = tx.subscribe();
+    let _ = tx.send(Broadcast::Chat(system_message(id, &username, &format!("joined {} [{}]", room, id), &room)));
+
+    // Report the live connected-user count so operators can see load.
+    let count = state.peers.lock().unwrap().len();
+    println!("💀[{}] {} online", Local::now().format("%H:%M:%S"), count);
 
-    // Initialize a buffer for incoming messages from the client
-    let mut line = String::new();
     loop {
         tokio::select! {
             // Handle messages sent by the client
-            result = reader.read_line(&mut line) => {
-                if result.unwrap() == 0 {
-                    break; // Exit loop if the client disconnects
-                }
-                // Create and broadcast a user message
-                let msg = ChatMessage {
-                    username: username.clone(),
-                    content: line.trim().to_string(),
-                    timestamp: Local::now().format("%H:%M:%S").to_string(),
-                    message_type: MessageType::UserMessage,
+            result = reader.next() => {
+                let incoming = match result {
+                    Some(Ok(msg)) => msg,
+                    _ => break, // Stream closed or a malformed frame: treat as disconnect.
                 };
-                let json = serde_json::to_string(&msg).unwrap();
-                tx.send(json).unwrap();
-                line.clear(); // Clear the buffer for the next message
+                let text = incoming.content.trim().to_string();
+                log_event(&ServerEvent::SentLine { id, content: text.clone() });
+
+                // Parse leading commands before treating the line as a normal message.
+                if let Some(rest) = text.strip_prefix("/w ") {
+                    // Direct message: "/w <user> <text>" is delivered only to <user>.
+                    let mut parts = rest.splitn(2, ' ');
+                    let target = parts.next().unwrap_or("").to_string();
+                    let body = parts.next().unwrap_or("").to_string();
+                    deliver_direct(&state, id, &username, &target, &body);
+                } else if let Some(target) = text.strip_prefix("/join ") {
+                    // Move this client from its current room to `target`.
+                    let target = target.trim().to_string();
+                    if !target.is_empty() && target != room {
+                        // Announce the departure in the old room, then drop its receiver.
+                        let old_room = room.clone();
+                        let _ = tx.send(Broadcast::Chat(system_message(id, &username, &format!("left {} [{}]", room, id), &room)));
+                        // Subscribe to the new room and announce our arrival there.
+                        room = target;
+                        tx = state.room(&room);
+                        rx = tx.subscribe();
+                        let _ = tx.send(Broadcast::Chat(system_message(id, &username, &format!("joined {} [{}]", room, id), &room)));
+                        // Our receiver for the old room was just dropped above; prune it
+                        // from the registry if we were its last subscriber.
+                        state.prune_room(&old_room);
+                    }
+                } else if text == "/who" {
+                    // List every user currently registered in the peer map.
+                    let names = {
+                        let peers = state.peers.lock().unwrap();
+                        peers.values().map(|p| p.username.clone()).collect::<Vec<_>>().join(", ")
+                    };
+                    let who = ChatMessage {
+                        id: Uuid::nil(),
+                        username: "server".to_string(),
+                        content: format!("online: {}", names),
+                        timestamp: Local::now().format("%H:%M:%S").to_string(),
+                        room: room.clone(),
+                        message_type: MessageType::SystemNotification,
+                    };
+                    if writer.send(who).await.is_err() {
+                        break; // Client went away mid-write.
+                    }
+                } else {
+                    // Create and broadcast a user message to the current room
+                    let msg = ChatMessage {
+                        id,
+                        username: username.clone(),
+                        content: text,
+                        timestamp: Local::now().format("%H:%M:%S").to_string(),
+                        room: room.clone(),
+                        message_type: MessageType::UserMessage,
+                    };
+                    let _ = tx.send(Broadcast::Chat(msg));
+                }
             }
             // Handle incoming broadcasts and send them to the client
             result = rx.recv() => {
-                let msg = result.unwrap();
-                writer.write_all(msg.as_bytes()).await.unwrap();
-                writer.write_all(b"\n").await.unwrap();
+                match result {
+                    Ok(Broadcast::Chat(msg)) => {
+                        if writer.send(msg).await.is_err() {
+                            break; // Broken pipe: the client is gone.
+                        }
+                    }
+                    // The server is shutting down: flush a final goodbye and close.
+                    Ok(Broadcast::Shutdown) => {
+                        let bye = system_message(Uuid::nil(), "server", "server shutting down", &room);
+                        let _ = writer.send(bye).await;
+                        break;
+                    }
+                    // We fell behind the broadcast channel: skip the dropped
+                    // messages and keep serving rather than tearing down the task.
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        println!(
+                            "💀[{}] {} lagged, skipped {} messages",
+                            Local::now().format("%H:%M:%S"), id, skipped
+                        );
+                    }
+                    // The channel closed (no senders left): nothing more to receive.
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            // Handle direct messages addressed specifically to this client
+            Some(msg) = dm_rx.recv() => {
+                if writer.send(msg).await.is_err() {
+                    break; // Broken pipe: the client is gone.
+                }
             }
         }
     }
 
-    // Send a system notification indicating the user has left
-    let leave_msg = ChatMessage {
-        username: username.clone(),
-        content: "left the chat".to_string(),
+    // Cleanup runs on every exit route below, so the leave notification can never
+    // be skipped regardless of which branch broke out of the loop.
+
+    // Drop ourselves from the registry so no further direct messages are routed here
+    state.peers.lock().unwrap().remove(&id);
+
+    // Announce the departure in whichever room the client was last in.
+    let _ = tx.send(Broadcast::Chat(system_message(id, &username, &format!("left {} [{}]", room, id), &room)));
+    log_event(&ServerEvent::Disconnected { id });
+
+    // Drop our subscription and prune the room if we were its last member.
+    drop(rx);
+    state.prune_room(&room);
+
+    // Log disconnection information and the updated live user count.
+    let count = state.peers.lock().unwrap().len();
+    println!("💀[{}] {} disconnected ({}), {} online",
+        Local::now().format("%H:%M:%S"), username, id, count);
+}
+
+// Emit a human-readable console log line for a protocol event.
+fn log_event(event: &ServerEvent) {
+    let ts = Local::now().format("%H:%M:%S");
+    match event {
+        ServerEvent::Connected { id, username } => {
+            println!("💀[{}] {} connected as {:?}", ts, id, username);
+        }
+        ServerEvent::SentLine { id, content } => {
+            println!("💀[{}] {} sent {:?}", ts, id, content);
+        }
+        ServerEvent::Disconnected { id } => {
+            println!("💀[{}] {} disconnected", ts, id);
+        }
+    }
+}
+
+// Build a system notification scoped to `room`, attributed to session `id`.
+fn system_message(id: Uuid, username: &str, content: &str, room: &str) -> ChatMessage {
+    ChatMessage {
+        id,
+        username: username.to_string(),
+        content: content.to_string(),
         timestamp: Local::now().format("%H:%M:%S").to_string(),
+        room: room.to_string(),
         message_type: MessageType::SystemNotification,
-    };
-    let leave_json = serde_json::to_string(&leave_msg).unwrap();
-    tx.send(leave_json).unwrap();
-    
-    // Log disconnection information
-    println!("💀[{}] {} disconnected", Local::now().format("%H:%M:%S"), username);
-} 
+    }
+}
+
+// Deliver a direct message from `from` to `target`, or send an error notification
+// back to the sender if the target is not currently online.
+fn deliver_direct(state: &Arc<Shared>, from_id: Uuid, from: &str, target: &str, body: &str) {
+    let peers = state.peers.lock().unwrap();
+    match peers.values().find(|p| p.username == target) {
+        Some(peer) => {
+            // Deliver the private message only to the target's channel.
+            let dm = ChatMessage {
+                id: from_id,
+                username: from.to_string(),
+                content: body.to_string(),
+                timestamp: Local::now().format("%H:%M:%S").to_string(),
+                room: "direct".to_string(),
+                message_type: MessageType::UserMessage,
+            };
+            let _ = peer.tx.send(dm);
+        }
+        None => {
+            // Notify the sender that the requested user is unknown.
+            if let Some(me) = peers.get(&from_id) {
+                let err = ChatMessage {
+                    id: Uuid::nil(),
+                    username: "server".to_string(),
+                    content: format!("no such user: {}", target),
+                    timestamp: Local::now().format("%H:%M:%S").to_string(),
+                    room: "direct".to_string(),
+                    message_type: MessageType::SystemNotification,
+                };
+                let _ = me.tx.send(err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    // The switch to length-delimited + JSON framing was meant to stop treating
+    // newlines inside a message body as a delimiter. Round-trip a message with
+    // an embedded newline over an in-memory duplex stream to prove a frame is
+    // read back whole rather than split on the byte that used to matter.
+    #[tokio::test]
+    async fn chat_message_round_trips_over_length_delimited_json_frames() {
+        let (client, server) = duplex(1024);
 
+        let mut writer = SymmetricallyFramed::new(
+            FramedWrite::new(client, LengthDelimitedCodec::new()),
+            SymmetricalJson::<ChatMessage>::default(),
+        );
+        let mut reader = SymmetricallyFramed::new(
+            FramedRead::new(server, LengthDelimitedCodec::new()),
+            SymmetricalJson::<ChatMessage>::default(),
+        );
 
+        let sent = ChatMessage {
+            id: Uuid::new_v4(),
+            username: "alice".to_string(),
+            content: "hello\nworld".to_string(),
+            timestamp: "12:00:00".to_string(),
+            room: "lobby".to_string(),
+            message_type: MessageType::UserMessage,
+        };
+
+        writer.send(sent.clone()).await.unwrap();
+        let received = reader.next().await.unwrap().unwrap();
+
+        assert_eq!(received.id, sent.id);
+        assert_eq!(received.username, sent.username);
+        assert_eq!(received.content, sent.content);
+        assert_eq!(received.room, sent.room);
+    }
+
+    // A receiver that falls behind a room's broadcast channel must observe
+    // `Lagged` rather than have `recv` panic or silently drop the channel, so
+    // the select! arm's skip-and-keep-serving branch actually gets exercised.
+    #[tokio::test]
+    async fn lagging_receiver_reports_lagged_instead_of_panicking() {
+        let (tx, mut rx) = broadcast::channel::<Broadcast>(2);
+
+        for i in 0..5 {
+            let _ = tx.send(Broadcast::Chat(system_message(
+                Uuid::nil(),
+                "server",
+                &format!("msg {i}"),
+                "lobby",
+            )));
+        }
+
+        match rx.recv().await {
+            Err(broadcast::error::RecvError::Lagged(skipped)) => assert!(skipped > 0),
+            other => panic!("expected Lagged, got {:?}", other),
+        }
+    }
+
+    // A session whose client already hung up drops its dm_rx half; sending to
+    // it must come back as an `Err` the caller can ignore instead of panicking
+    // like the old unwrap()-based version would have.
+    #[tokio::test]
+    async fn sending_to_a_dropped_dm_receiver_is_a_harmless_error() {
+        let (dm_tx, dm_rx) = mpsc::unbounded_channel::<ChatMessage>();
+        drop(dm_rx);
+
+        let msg = system_message(Uuid::nil(), "server", "hi", "lobby");
+        assert!(dm_tx.send(msg).is_err());
+    }
+
+    // Two different rooms get independent broadcast channels, so a chat
+    // message sent to one room must never reach a subscriber of another.
+    #[tokio::test]
+    async fn rooms_are_isolated_from_each_other() {
+        let state = Shared::new();
+        let tx_a = state.room("a");
+        let tx_b = state.room("b");
+        let mut rx_a = tx_a.subscribe();
+        let mut rx_b = tx_b.subscribe();
+
+        let msg = system_message(Uuid::nil(), "server", "hello a", "a");
+        tx_a.send(Broadcast::Chat(msg)).unwrap();
+
+        match rx_a.recv().await {
+            Ok(Broadcast::Chat(received)) => assert_eq!(received.content, "hello a"),
+            other => panic!("expected Chat, got {:?}", other),
+        }
+        assert!(matches!(rx_b.try_recv(), Err(broadcast::error::TryRecvError::Empty)));
+    }
+
+    // The Ctrl+C handler fans `Broadcast::Shutdown` out to every room; a
+    // subscriber must see it and be able to build the same "server shutting
+    // down" system notification the connection loop sends before closing.
+    #[tokio::test]
+    async fn shutdown_broadcast_reaches_subscriber_as_system_notification() {
+        let state = Shared::new();
+        let tx = state.room("lobby");
+        let mut rx = tx.subscribe();
+
+        tx.send(Broadcast::Shutdown).unwrap();
+
+        match rx.recv().await {
+            Ok(Broadcast::Shutdown) => {
+                let bye = system_message(Uuid::nil(), "server", "server shutting down", "lobby");
+                assert_eq!(bye.content, "server shutting down");
+                assert!(matches!(bye.message_type, MessageType::SystemNotification));
+            }
+            other => panic!("expected Shutdown, got {:?}", other),
+        }
+    }
+}